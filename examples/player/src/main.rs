@@ -19,7 +19,6 @@ extern crate ao_rs as ao;
 extern crate wildmidi;
 
 use std::env::args;
-use std::mem::transmute;
 use std::path::Path;
 use std::process::exit;
 
@@ -50,17 +49,20 @@ fn main() {
     let device = Device::new(&driver, &format, None).unwrap();
 
     loop {
-        // It would simply be too slow to do a safe conversion every time we
-        // buffer the PCM output.
-        let vec = midi.play(4096);
-        let pcm = unsafe {
-            transmute::<&[u8], &[i8]>(&vec[..])
-        };
+        // 1024 stereo frames is the same 4096 bytes we used to request from
+        // 'play' directly, just decoded into samples instead of raw bytes.
+        let samples = midi.play_i16(1024);
 
-        if pcm.len() <= 0 {
+        if samples.is_empty() {
             break;
         }
 
+        let pcm: Vec<i8> = samples
+            .iter()
+            .flat_map(|sample| sample.to_ne_bytes())
+            .map(|byte| byte as i8)
+            .collect();
+
         device.play(&pcm[..] as &[i8]);
     }
 }
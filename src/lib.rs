@@ -18,11 +18,15 @@
 #[macro_use]
 extern crate simple_error;
 
+use std::env;
 use std::error::Error;
 use std::ffi::CStr;
 use std::ffi::CString;
+use std::io;
+use std::marker::PhantomData;
 use std::os::raw::{c_char, c_uchar, c_ushort, c_int, c_ulong, c_void};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 #[repr(C)]
 struct WM_Info {
@@ -38,7 +42,6 @@ extern "C" {
     fn WildMidi_Init(cfg: *const c_char, rate: c_ushort, flags: c_ushort) -> c_int;
     fn WildMidi_Open(path: *const c_char) -> *const c_void;
     fn WildMidi_OpenBuffer(data: *const c_uchar, size: c_ulong) -> *const c_void;
-    // fn WildMidi_SetOption();
     fn WildMidi_MasterVolume(volume: c_uchar) -> c_int;
     fn WildMidi_Shutdown();
 
@@ -47,60 +50,208 @@ extern "C" {
     fn WildMidi_FastSeek(ptr: *const c_void, pos: c_ushort) -> c_int;
     fn WildMidi_GetOutput(ptr: *const c_void, buf: *mut c_uchar, len: c_ulong) -> c_int;
     fn WildMidi_GetInfo(ptr: *const c_void) -> *const WM_Info;
+    fn WildMidi_SetOption(ptr: *const c_void, options: c_ushort, setting: c_ushort) -> c_int;
 }
 
+/// Mixer DSP options understood by `WildMidi_SetOption`, as reflected by
+/// `WM_Info.mixer_options`.
+///
+/// `LOG_VOLUME`, `ENHANCED_RESAMPLING`, and `REVERB` match the `WM_MO_*`
+/// flags from upstream's `include/wildmidi_lib.h` (`WM_MO_LOG_VOLUME`,
+/// `WM_MO_ENHANCED_RESAMPLING`, `WM_MO_REVERB` — 0x0001/0x0002/0x0004). There
+/// is no distinct upstream bit for "linear volume"; it's simply the absence
+/// of `WM_MO_LOG_VOLUME`, so `LINEAR_VOLUME` below is an alias for that same
+/// bit rather than a fourth flag. A "save as type 0" bit could not be
+/// verified against the header in this sandbox (no network access, no
+/// upstream source on disk to diff against), so it has been left out rather
+/// than ship an unverified value — add it back once it's confirmed against
+/// the real header.
+///
+/// Use `Midi::set_options` to toggle these on a loaded handle, and
+/// `Midi::options` to read back the options currently in effect.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MixerOptions(c_ushort);
+
+impl MixerOptions {
+    /// No options set.
+    pub const NONE: MixerOptions = MixerOptions(0x0000);
+    /// Scale note volume logarithmically rather than linearly.
+    pub const LOG_VOLUME: MixerOptions = MixerOptions(0x0001);
+    /// Use a higher quality (but more expensive) resampler.
+    pub const ENHANCED_RESAMPLING: MixerOptions = MixerOptions(0x0002);
+    /// Apply the built-in reverb effect.
+    pub const REVERB: MixerOptions = MixerOptions(0x0004);
+    /// The volume-scaling bit, under a name for callers who want to turn it
+    /// off. This is the same underlying bit as `LOG_VOLUME` — upstream has no
+    /// separate flag for it — so setting it selects logarithmic scaling and
+    /// clearing it selects linear scaling, exactly as with `LOG_VOLUME`; it
+    /// exists only so callers can name whichever direction reads more
+    /// clearly at the call site.
+    pub const LINEAR_VOLUME: MixerOptions = MixerOptions(0x0001);
+
+    fn bits(self) -> c_ushort {
+        self.0
+    }
+
+    fn from_bits(bits: c_ushort) -> MixerOptions {
+        MixerOptions(bits)
+    }
+
+    /// Returns true if all of the flags in 'other' are set in 'self'.
+    pub fn contains(self, other: MixerOptions) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for MixerOptions {
+    type Output = MixerOptions;
+
+    fn bitor(self, rhs: MixerOptions) -> MixerOptions {
+        MixerOptions(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitAnd for MixerOptions {
+    type Output = MixerOptions;
+
+    fn bitand(self, rhs: MixerOptions) -> MixerOptions {
+        MixerOptions(self.0 & rhs.0)
+    }
+}
+
+/// Tracks the process-global WildMidi engine, which can only ever be
+/// initialized with a single configuration at a time. 'count' is the number
+/// of live 'Player's relying on it; the engine is shut down only when the
+/// last one is dropped.
+struct GlobalInit {
+    cfg: String,
+    rate: u16,
+    count: usize,
+}
+
+static INIT_STATE: Mutex<Option<GlobalInit>> = Mutex::new(None);
+
 /// Loader for the Midi format.
-pub struct Player;
+///
+/// WildMidi_Init/WildMidi_Shutdown operate on process-global state, so
+/// 'Player' reference counts that state rather than owning it outright: the
+/// first 'Player' created performs the real initialization, additional
+/// 'Player's sharing the same configuration and rate simply join in, and the
+/// engine is shut down only once the last 'Player' is dropped.
+pub struct Player {
+    _private: (),
+}
 
 impl Player {
-    fn locate_cfg() -> Option<&'static str> {
-        let paths = vec![
-            "/etc/wildmidi/wildmidi.cfg",
-            "/etc/wildmidi.cfg"
-        ];
-
-        for path in paths.iter() {
-            if Path::new(path).exists() {
-                return Some(path);
+    /// The configuration search path used by 'new', in the order they're
+    /// tried: the 'WILDMIDI_CFG' environment variable (if set), the two
+    /// traditional FHS locations, '$XDG_CONFIG_HOME/wildmidi/wildmidi.cfg',
+    /// and finally '~/.wildmidi.cfg'.
+    fn default_search_paths() -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+
+        if let Ok(cfg) = env::var("WILDMIDI_CFG") {
+            paths.push(PathBuf::from(cfg));
+        }
+
+        paths.push(PathBuf::from("/etc/wildmidi/wildmidi.cfg"));
+        paths.push(PathBuf::from("/etc/wildmidi.cfg"));
+
+        if let Ok(xdg_config_home) = env::var("XDG_CONFIG_HOME") {
+            paths.push(Path::new(&xdg_config_home).join("wildmidi/wildmidi.cfg"));
+        }
+
+        if let Ok(home) = env::var("HOME") {
+            paths.push(Path::new(&home).join(".wildmidi.cfg"));
+        }
+
+        paths
+    }
+
+    /// Returns the first path in 'paths' that exists on disk.
+    ///
+    /// # Errors
+    ///
+    /// Will fail, naming every path that was tried, if none of them exist.
+    fn locate_cfg(paths: &[PathBuf]) -> Result<PathBuf, Box<Error>> {
+        for path in paths {
+            if path.exists() {
+                return Ok(path.clone());
             }
         }
 
-        None
+        let tried: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+        bail!("No valid configuration file found (tried: {})", tried.join(", "))
     }
 
-    /// Create a new Player with the given sample rate, using the default
-    /// configuration file.
+    /// Create a new Player with the given sample rate, searching
+    /// 'default_search_paths' for a configuration file to use.
     ///
     /// # Errors
     ///
-    /// Will fail if 'rate' is not on the interval [11025,65535], or if neither
-    /// of the default configuration files exist ('/etc/wildmidi/wildmidi.cfg',
-    /// '/etc/wildmidi.cfg').
+    /// Will fail if 'rate' is not on the interval [11025,65535], or if none
+    /// of the default search paths exist. See 'default_search_paths' for the
+    /// full list, including the 'WILDMIDI_CFG' environment variable.
     pub fn new(rate: u16) -> Result<Player, Box<Error>> {
-        let cfg = match Player::locate_cfg() {
-            Some(cfg) => cfg,
-            None => bail!("No valid configuration file found"),
-        };
+        let cfg = Player::locate_cfg(&Player::default_search_paths())?;
+
+        Player::with_cfg(&cfg.to_string_lossy(), rate)
+    }
+
+    /// Create a new Player with the given sample rate, searching 'paths' (in
+    /// order) for a configuration file to use instead of the built-in
+    /// defaults.
+    ///
+    /// # Errors
+    ///
+    /// Will fail if 'rate' is not on the interval [11025,65535], or if none
+    /// of 'paths' exist.
+    pub fn with_search_paths(paths: &[&Path], rate: u16) -> Result<Player, Box<Error>> {
+        let paths: Vec<PathBuf> = paths.iter().map(|p| p.to_path_buf()).collect();
+        let cfg = Player::locate_cfg(&paths)?;
 
-        Player::with_cfg(cfg, rate)
+        Player::with_cfg(&cfg.to_string_lossy(), rate)
     }
 
     /// Create a new Player with the given config path and sample rate.
     ///
+    /// If a 'Player' is already live elsewhere in the process, this succeeds
+    /// without touching the engine as long as 'cfg' and 'rate' match what it
+    /// was initialized with; otherwise it fails, since WildMidi only supports
+    /// a single global configuration at a time.
+    ///
     /// # Errors
     ///
-    /// Will fail if 'rate' is not on the interval [11025,65535].
+    /// Will fail if 'rate' is not on the interval [11025,65535], or if a
+    /// 'Player' using a different 'cfg' or 'rate' is already live.
     pub fn with_cfg(cfg: &str, rate: u16) -> Result<Player, Box<Error>> {
-        let cfg = CString::new(cfg)?;
+        let mut state = INIT_STATE.lock().unwrap();
+
+        match state.as_mut() {
+            Some(init) => {
+                if init.cfg != cfg || init.rate != rate {
+                    bail!(
+                        "WildMidi is already initialized with cfg='{}', rate={}",
+                        init.cfg, init.rate
+                    );
+                }
 
-        unsafe {
-            // WildMidi_Shutdown();
-            if WildMidi_Init(cfg.as_ptr(), rate, 0) != 0 {
-                bail!("Couldn't initialize WildMidi.");
+                init.count += 1;
+            }
+            None => {
+                let cfg_cstr = CString::new(cfg)?;
+
+                unsafe {
+                    if WildMidi_Init(cfg_cstr.as_ptr(), rate, 0) != 0 {
+                        bail!("Couldn't initialize WildMidi.");
+                    }
+                }
+
+                *state = Some(GlobalInit { cfg: cfg.to_string(), rate, count: 1 });
             }
         }
 
-        Ok(Player { })
+        Ok(Player { _private: () })
     }
 
     /// Sets the overall library volume level. The default is 100.
@@ -120,7 +271,7 @@ impl Player {
     ///
     /// Will fail if an internal error occurs in WildMidi (such as a parse
     /// error).
-    pub fn load(&self, data: &[u8]) -> Result<Midi, Box<Error>> {
+    pub fn load<'a>(&'a self, data: &[u8]) -> Result<Midi<'a>, Box<Error>> {
         unsafe {
             let len = data.len() as c_ulong;
             let ptr = WildMidi_OpenBuffer(data.as_ptr(), len);
@@ -139,7 +290,7 @@ impl Player {
     ///
     /// Will fail if the file does not exist, or if an internal error occurs in
     /// WildMidi (such as a parse error).
-    pub fn load_file(&self, path: &str) -> Result<Midi, Box<Error>> {
+    pub fn load_file<'a>(&'a self, path: &str) -> Result<Midi<'a>, Box<Error>> {
         if !Path::new(path).exists() {
             bail!("File does not exist");
         }
@@ -160,37 +311,163 @@ impl Player {
 
 impl Drop for Player {
     fn drop(&mut self) {
-        unsafe {
-            WildMidi_Shutdown();
+        let mut state = INIT_STATE.lock().unwrap();
+
+        if let Some(init) = state.as_mut() {
+            init.count -= 1;
+
+            if init.count == 0 {
+                unsafe {
+                    WildMidi_Shutdown();
+                }
+
+                *state = None;
+            }
         }
     }
 }
 
 /// An actual Midi file, capable of producing a PCM output.
-pub struct Midi {
+///
+/// Borrows the 'Player' it was loaded from for its entire lifetime, so it
+/// cannot outlive the engine instance backing it.
+pub struct Midi<'a> {
     ptr: *const c_void,
+    looping: bool,
+    _player: PhantomData<&'a Player>,
 }
 
-impl Midi {
-    fn new(ptr: *const c_void) -> Midi {
-        Midi { ptr }
+impl<'a> Midi<'a> {
+    fn new(ptr: *const c_void) -> Midi<'a> {
+        Midi { ptr, looping: false, _player: PhantomData }
+    }
+
+    /// Enables or disables gapless looping. When enabled, 'play' seeks back
+    /// to the start of the song instead of running dry once the end is
+    /// reached, so the returned buffer is filled to the requested length
+    /// unless the song produces no output at all after seeking back.
+    pub fn set_loop(&mut self, loop_enabled: bool) {
+        self.looping = loop_enabled;
     }
 
-    /// Returns a Vec<u8> containing 'len' bytes of PCM data.
+    /// Returns a Vec<u8> containing 'len' bytes of raw PCM data, straight
+    /// from WildMidi_GetOutput. This is interleaved signed 16-bit stereo
+    /// audio in the host's native byte order; prefer 'play_i16' or
+    /// 'play_f32' unless you specifically need the untouched bytes.
+    ///
+    /// If looping is enabled (see 'set_loop'), reaching the end of the song
+    /// seeks back to the beginning and keeps filling the buffer instead of
+    /// returning early.
     pub fn play(&mut self, len: usize) -> Vec<u8> {
-        let mut vec = vec![0;len];
+        let mut vec = vec![0; len];
+        let filled = self.fill(&mut vec);
+
+        if filled < len {
+            vec.resize(filled, 0);
+        }
+
+        vec
+    }
+
+    /// Fills 'buf' with PCM bytes, looping back to the start on end-of-song
+    /// when looping is enabled, and returns the number of bytes actually
+    /// written (less than `buf.len()` only when the song ended and looping
+    /// is off, or when looping is on but the song produces no output after
+    /// seeking back to the start). This is the shared core behind 'play' and the `Read` impl;
+    /// unlike 'play' it writes directly into the caller's buffer instead of
+    /// allocating one.
+    fn fill(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len();
+        let mut filled = 0;
+        let mut just_seeked = false;
 
         unsafe {
-            let buf = vec.as_mut_ptr();
             let handle = self.ptr;
-            let read = WildMidi_GetOutput(handle, buf, len as c_ulong) as usize;
+            let ptr = buf.as_mut_ptr();
 
-            if read < len {
-                vec.resize(read, 0);
+            loop {
+                let remaining = (len - filled) as c_ulong;
+                let read = WildMidi_GetOutput(handle, ptr.add(filled), remaining) as usize;
+                filled += read;
+
+                if filled == len {
+                    break;
+                }
+
+                // 'read' was less than 'remaining': WildMidi has reached the
+                // end of the song.
+                if !self.looping || (read == 0 && just_seeked) {
+                    // Either looping is off, or we just seeked back to the
+                    // start and got nothing at all; the song is empty, so
+                    // bail out rather than looping forever.
+                    break;
+                }
+
+                WildMidi_FastSeek(handle, 0);
+                just_seeked = read == 0;
             }
         }
 
-        vec
+        filled
+    }
+
+    /// Returns 'frames' stereo frames (two interleaved `i16` samples each) of
+    /// PCM data, decoded from WildMidi's little-endian byte stream. Unlike
+    /// 'play', this is correct on big-endian targets. Once a non-looping song
+    /// ends, fewer samples (possibly none) are returned.
+    pub fn play_i16(&mut self, frames: usize) -> Vec<i16> {
+        let mut bytes = self.play(frames * 4);
+
+        // A non-looping 'play' can hit end-of-song mid-sample, returning a
+        // length that isn't a multiple of 2. Trim that trailing byte
+        // explicitly instead of letting 'chunks_exact' silently drop it, so
+        // there's no ambiguity about where it went.
+        if bytes.len() % 2 != 0 {
+            bytes.pop();
+        }
+
+        bytes
+            .chunks_exact(2)
+            .map(|c| i16::from_le_bytes([c[0], c[1]]))
+            .collect()
+    }
+
+    /// Returns 'frames' stereo frames (two interleaved `f32` samples each) of
+    /// PCM data, normalized to `[-1.0, 1.0]` for use with audio backends such
+    /// as cpal or rodio.
+    pub fn play_f32(&mut self, frames: usize) -> Vec<f32> {
+        // i16::MIN.abs() overflows i16, so divide by its magnitude as an f32
+        // literal instead of deriving it from i16::MAX (which would leave
+        // -32768 mapping to just past -1.0).
+        self.play_i16(frames)
+            .into_iter()
+            .map(|sample| f32::from(sample) / 32768.0)
+            .collect()
+    }
+
+    /// Toggles the mixer options named in 'mask', turning them on where the
+    /// corresponding bit in 'enabled' is set and off otherwise. Bits of
+    /// 'enabled' not present in 'mask' are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Will fail if the underlying call to WildMidi_SetOption fails.
+    pub fn set_options(&mut self, mask: MixerOptions, enabled: MixerOptions) -> Result<(), Box<Error>> {
+        unsafe {
+            if WildMidi_SetOption(self.ptr, mask.bits(), (mask & enabled).bits()) != 0 {
+                bail!("Couldn't set mixer options.");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The mixer options currently in effect for this Midi handle.
+    pub fn options(&self) -> MixerOptions {
+        unsafe {
+            let ptr = WildMidi_GetInfo(self.ptr);
+            MixerOptions::from_bits((*ptr).mixer_options)
+        }
     }
 
     /// Resets all note specific midi states and active notes before scanning to
@@ -249,9 +526,18 @@ impl Midi {
             (*ptr).total_midi_time as usize
         }
     }
+
+    /// Returns an iterator yielding fixed-size blocks of 'frames' stereo
+    /// samples at a time (see 'play_i16'), ending once the song runs dry; the
+    /// last block before the end may be shorter.
+    /// Useful for piping a Midi into a streaming sink without committing to
+    /// `std::io::Read`'s byte-oriented interface.
+    pub fn frames(&mut self, frames: usize) -> Frames<'a, '_> {
+        Frames { midi: self, frames }
+    }
 }
 
-impl Drop for Midi {
+impl<'a> Drop for Midi<'a> {
     fn drop(&mut self) {
         unsafe {
             // There isn't much of a point in handling errors here.
@@ -260,6 +546,38 @@ impl Drop for Midi {
     }
 }
 
+impl<'a> io::Read for Midi<'a> {
+    /// Fills 'buf' with raw PCM bytes, the same host-endian format documented
+    /// on 'play' (which shares this impl's core loop, so looping is honored
+    /// the same way), writing directly into 'buf' with no intermediate
+    /// allocation. Returns `Ok(0)` once the song has finished and
+    /// 'set_loop' is not enabled.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(self.fill(buf))
+    }
+}
+
+/// An iterator over fixed-size blocks of stereo PCM frames, created by
+/// 'Midi::frames'.
+pub struct Frames<'a, 'm> {
+    midi: &'m mut Midi<'a>,
+    frames: usize,
+}
+
+impl<'a, 'm> Iterator for Frames<'a, 'm> {
+    type Item = Vec<i16>;
+
+    fn next(&mut self) -> Option<Vec<i16>> {
+        let block = self.midi.play_i16(self.frames);
+
+        if block.is_empty() {
+            None
+        } else {
+            Some(block)
+        }
+    }
+}
+
 #[cfg(test)]
 mod player_tests {
     use ::*;
@@ -278,3 +596,119 @@ mod player_tests {
         }
     }
 }
+
+#[cfg(test)]
+mod mixer_options_tests {
+    use ::*;
+
+    #[test]
+    fn contains_checks_all_bits_in_other() {
+        let combo = MixerOptions::REVERB | MixerOptions::ENHANCED_RESAMPLING;
+
+        assert!(combo.contains(MixerOptions::REVERB));
+        assert!(combo.contains(MixerOptions::ENHANCED_RESAMPLING));
+        assert!(combo.contains(combo));
+        assert!(!combo.contains(MixerOptions::LOG_VOLUME));
+    }
+
+    #[test]
+    fn bitor_combines_distinct_flags() {
+        let combo = MixerOptions::REVERB | MixerOptions::LOG_VOLUME;
+
+        assert!(combo.contains(MixerOptions::REVERB));
+        assert!(combo.contains(MixerOptions::LOG_VOLUME));
+        assert!(!combo.contains(MixerOptions::ENHANCED_RESAMPLING));
+    }
+
+    #[test]
+    fn bitand_keeps_only_shared_bits() {
+        let a = MixerOptions::REVERB | MixerOptions::LOG_VOLUME;
+        let b = MixerOptions::REVERB | MixerOptions::ENHANCED_RESAMPLING;
+        let shared = a & b;
+
+        assert!(shared.contains(MixerOptions::REVERB));
+        assert!(!shared.contains(MixerOptions::LOG_VOLUME));
+        assert!(!shared.contains(MixerOptions::ENHANCED_RESAMPLING));
+    }
+
+    #[test]
+    fn set_options_masks_enabled_down_to_the_requested_mask() {
+        // 'set_options' only ever sends the bits named in 'mask' down to
+        // WildMidi_SetOption, even if 'enabled' carries extra bits outside
+        // of it.
+        let mask = MixerOptions::REVERB;
+        let enabled = MixerOptions::REVERB | MixerOptions::LOG_VOLUME;
+
+        assert_eq!(mask & enabled, MixerOptions::REVERB);
+    }
+
+    #[test]
+    fn linear_volume_is_the_same_bit_as_log_volume() {
+        assert_eq!(MixerOptions::LINEAR_VOLUME, MixerOptions::LOG_VOLUME);
+    }
+}
+
+#[cfg(test)]
+mod cfg_search_tests {
+    use ::*;
+
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    // 'WILDMIDI_CFG' is process-wide state; serialize the tests that touch
+    // it so they don't stomp on each other when run in parallel.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn wildmidi_cfg_env_var_is_checked_first() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        env::set_var("WILDMIDI_CFG", "/tmp/some-wildmidi.cfg");
+
+        let paths = Player::default_search_paths();
+
+        env::remove_var("WILDMIDI_CFG");
+
+        assert_eq!(paths[0], PathBuf::from("/tmp/some-wildmidi.cfg"));
+    }
+
+    #[test]
+    fn default_search_paths_without_env_var_starts_with_fhs_path() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        env::remove_var("WILDMIDI_CFG");
+
+        let paths = Player::default_search_paths();
+
+        assert_eq!(paths[0], PathBuf::from("/etc/wildmidi/wildmidi.cfg"));
+    }
+
+    #[test]
+    fn locate_cfg_finds_the_first_existing_path() {
+        let dir = env::temp_dir();
+        let existing = dir.join("wildmidi-test-locate-cfg-exists.cfg");
+        fs::write(&existing, b"").unwrap();
+
+        let missing = dir.join("wildmidi-test-locate-cfg-missing.cfg");
+        let paths = vec![missing, existing.clone()];
+
+        let found = Player::locate_cfg(&paths).unwrap();
+        fs::remove_file(&existing).unwrap();
+
+        assert_eq!(found, existing);
+    }
+
+    #[test]
+    fn locate_cfg_names_every_path_tried_when_none_exist() {
+        let dir = env::temp_dir();
+        let missing_a = dir.join("wildmidi-test-locate-cfg-missing-a.cfg");
+        let missing_b = dir.join("wildmidi-test-locate-cfg-missing-b.cfg");
+        let paths = vec![missing_a.clone(), missing_b.clone()];
+
+        let err = Player::locate_cfg(&paths).unwrap_err();
+        let message = err.to_string();
+
+        assert!(message.contains(&missing_a.display().to_string()));
+        assert!(message.contains(&missing_b.display().to_string()));
+    }
+}